@@ -1,4 +1,12 @@
-use std::{collections::HashMap, fs::File, io::Write};
+mod playlist;
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::File,
+    io::Write,
+    sync::Arc,
+    time::Duration,
+};
 
 use clap::{command, Parser};
 use crypto::{
@@ -6,72 +14,10 @@ use crypto::{
     blockmodes::{self},
     buffer::{self, ReadBuffer, WriteBuffer},
 };
-
-const EXT_X_VERSION: &str = "#EXT-X-VERSION:";
-
-const EXT_X_TARGETDURATION: &str = "#EXT-X-TARGETDURATION:";
-
-const EXT_X_PLAYLIST_TYPE: &str = "#EXT-X-PLAYLIST-TYPE:";
-
-const EXT_X_MEDIA_SEQUENCE: &str = "#EXT-X-MEDIA-SEQUENCE:";
-
-const EXT_X_KEY: &str = "#EXT-X-KEY:";
-
-const METHOD: &str = "METHOD=";
-
-const URL: &str = "URI=";
-
-const IV: [u8; 16] = [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
-
-#[derive(Debug, Clone)]
-struct Ext {
-    version: Option<u32>,
-    target_duration: Option<u32>,
-    play_list_type: Option<String>,
-    media_sequence: Option<u32>,
-    key: Option<HashMap<String, String>>,
-    uri_list: Option<Vec<String>>,
-}
-
-impl Ext {
-    fn new() -> Self {
-        Self {
-            version: None,
-            target_duration: None,
-            play_list_type: None,
-            media_sequence: None,
-            key: None,
-            uri_list: Some(Vec::new()),
-        }
-    }
-
-    fn set_version(&mut self, version: u32) {
-        self.version = Some(version);
-    }
-
-    fn set_target_duration(&mut self, target_duration: u32) {
-        self.target_duration = Some(target_duration);
-    }
-
-    fn set_play_list_type(&mut self, play_list_type: String) {
-        self.play_list_type = Some(play_list_type);
-    }
-
-    fn set_media_sequence(&mut self, media_sequence: u32) {
-        self.media_sequence = Some(media_sequence);
-    }
-    fn set_key(&mut self, key: HashMap<String, String>) {
-        self.key = Some(key);
-    }
-
-    fn set_uri_list(&mut self, uri: String) {
-        if let Some(vec) = &mut self.uri_list {
-            vec.push(uri);
-        } else {
-            panic!("error");
-        };
-    }
-}
+use futures::stream::{self, StreamExt};
+use indicatif::{ProgressBar, ProgressStyle};
+use playlist::{Key, MediaPlaylist, Playlist};
+use tokio::sync::Mutex;
 
 #[derive(Parser, Debug, Clone)]
 #[command(version, about, long_about = None)]
@@ -95,19 +41,85 @@ struct M3u8Command {
     /// 后缀名
     #[arg(short, long, default_value = ".ts")]
     suffix: String,
+
+    /// 清晰度选择,当 m_url 指向 master playlist 时生效:
+    /// "highest"、"lowest",或显式的分辨率(如 1920x1080)/带宽数值
+    #[arg(short, long, default_value = "highest")]
+    variant: String,
+
+    /// 并发下载的分片数量
+    #[arg(short, long, default_value_t = 8)]
+    concurrency: usize,
+
+    /// 断点续传缓存目录。设置后每个分片会写入该目录下的独立文件,
+    /// 重启时若对应文件已存在则直接复用,跳过已完成部分
+    #[arg(long)]
+    cache_dir: Option<String>,
+
+    /// 分片 / 密钥 / m3u8 请求的最大尝试次数(含首次请求)
+    #[arg(long, default_value_t = 4)]
+    max_retries: u32,
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let m3u8 = M3u8Command::parse();
-    let mut ext = Ext::new();
-    let response = reqwest::get(&m3u8.m_url).await?;
-    let text = response.text().await?;
-    analyze(&mut ext, text, &m3u8.suffix).await?;
-    down_load(&ext, &m3u8).await?;
+    let text = fetch_playlist(&m3u8.m_url, m3u8.max_retries).await?;
+
+    let (media, base_url) = match playlist::parse(&text) {
+        Playlist::Media(media) => (media, m3u8.domain_name.clone()),
+        Playlist::Master(master) => {
+            let variant = playlist::select_variant(&master.variants, &m3u8.variant);
+            eprintln!(
+                "selected variant {}: bandwidth={:?} average_bandwidth={:?} resolution={:?} codecs={:?}",
+                variant.uri,
+                variant.bandwidth,
+                variant.average_bandwidth,
+                variant.resolution,
+                variant.codecs,
+            );
+            let variant_url = join_url(&m3u8.domain_name, &variant.uri);
+            let variant_text = fetch_playlist(&variant_url, m3u8.max_retries).await?;
+            let base_url = resolve_variant_base(&m3u8.domain_name, &variant.uri);
+            match playlist::parse(&variant_text) {
+                Playlist::Media(media) => (media, base_url),
+                Playlist::Master(_) => {
+                    return Err("variant playlist is itself a master playlist".into())
+                }
+            }
+        }
+    };
+
+    down_load(&media, &base_url, &m3u8).await?;
     Ok(())
 }
 
+/// Resolves the base URL that a selected variant's own segments and keys are
+/// relative to. A variant playlist's URIs are relative to *its own*
+/// directory, not the master playlist's, so e.g. a variant at
+/// `variant_hi/index.m3u8` must resolve sibling segments against
+/// `domain_name/variant_hi`, not `domain_name`.
+fn resolve_variant_base(domain_name: &str, variant_uri: &str) -> String {
+    match variant_uri.rfind('/') {
+        Some(slash) => format!("{domain_name}/{}", &variant_uri[..slash]),
+        None => domain_name.to_string(),
+    }
+}
+
+/// Joins a base URL and a relative URI with `/`, the one join rule every
+/// fetch site (playlist, segment, key) should share.
+fn join_url(base: &str, uri: &str) -> String {
+    format!("{base}/{uri}")
+}
+
+async fn fetch_playlist(url: &str, max_retries: u32) -> Result<String, Box<dyn std::error::Error>> {
+    with_retry(url, max_retries, || async move {
+        let response = reqwest::get(url).await?.error_for_status()?;
+        Ok(response.text().await?)
+    })
+    .await
+}
+
 async fn decrypt(
     key: &[u8],
     iv: &[u8],
@@ -130,121 +142,314 @@ async fn decrypt(
     Ok(final_result)
 }
 
-async fn analyze(
-    ext: &mut Ext,
-    m3u8_value: String,
-    suffix: &str,
+async fn down_load(
+    media: &MediaPlaylist,
+    base_url: &str,
+    m3u8: &M3u8Command,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    m3u8_value.split("\n").for_each(|line| {
-        if line.starts_with(EXT_X_VERSION) {
-            let version = acquire_u32(&line, EXT_X_VERSION);
-            ext.set_version(version);
-        }
-        if line.starts_with(EXT_X_TARGETDURATION) {
-            let target_duration = acquire_u32(&line, EXT_X_TARGETDURATION);
-            ext.set_target_duration(target_duration);
-        }
-
-        if line.starts_with(EXT_X_PLAYLIST_TYPE) {
-            let play_list_type = acquire_string(&line, EXT_X_PLAYLIST_TYPE);
-            ext.set_play_list_type(play_list_type);
-        }
-
-        if line.starts_with(EXT_X_MEDIA_SEQUENCE) {
-            let media_sequence = acquire_u32(&line, EXT_X_MEDIA_SEQUENCE);
-            ext.set_media_sequence(media_sequence);
-        }
-
-        if line.starts_with(EXT_X_KEY) {
-            let mut key_hash: HashMap<String, String> = HashMap::new();
-            line.split(EXT_X_KEY)
-                .last()
-                .unwrap()
-                .split(",")
-                .for_each(|key| {
-                    if key.starts_with(METHOD) {
-                        key_hash.insert(
-                            METHOD.to_string(),
-                            key.split(METHOD).last().unwrap().to_string(),
-                        );
-                    }
-                    if key.starts_with(URL) {
-                        key_hash.insert(
-                            URL.to_string(),
-                            key.split(URL).last().unwrap().to_string().replace("\"", ""),
-                        );
-                    }
-                });
-
-            ext.set_key(key_hash);
-        }
-
-        if line.contains(suffix) {
-            ext.set_uri_list(line.to_string());
-        }
-    });
-    Ok(())
-}
-
-async fn down_load(ext: &Ext, m3u8: &M3u8Command) -> Result<(), Box<dyn std::error::Error>> {
     let mut path = std::path::PathBuf::new();
     path.push(format!("{}/{}{}", m3u8.l_dir, m3u8.file_name, m3u8.suffix));
     let mut write_file = File::create(path).expect("file not found");
 
-    if let Some(uri_list) = &ext.uri_list {
-        if let Some(key_value) = &ext.key {
-            let mut count: u32 = 1;
-            if key_value.get(METHOD).unwrap().is_empty() || key_value.get(URL).unwrap().is_empty() {
-                for uri in uri_list.iter() {
-                    let mut buf = request_resource(&m3u8.domain_name, uri).await?;
-                    let _ = write_file.write_all(&mut buf).unwrap();
-                    println!("{}/{}", count, uri_list.len());
-                    count += 1;
+    let progress = ProgressBar::new(media.segments.len() as u64);
+    progress.set_style(
+        ProgressStyle::with_template(
+            "{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {pos}/{len} segments ({eta}) {msg}",
+        )
+        .unwrap()
+        .progress_chars("#>-"),
+    );
+
+    if let Some(cache_dir) = &m3u8.cache_dir {
+        std::fs::create_dir_all(cache_dir)?;
+    }
+    let key_cache: Arc<Mutex<HashMap<String, String>>> = Arc::new(Mutex::new(HashMap::new()));
+
+    let domain_name = base_url;
+    let media_sequence = media.media_sequence.unwrap_or(0);
+    let max_retries = m3u8.max_retries;
+
+    let mut fetches = stream::iter(media.segments.iter().enumerate())
+        .map(|(index, segment)| {
+            let cache_dir = m3u8.cache_dir.clone();
+            let key_cache = key_cache.clone();
+            async move {
+                if let Some(cache_dir) = &cache_dir {
+                    // A part-file on disk is proof on its own that this segment was
+                    // already fetched, independent of whether it ever reached the
+                    // contiguous prefix written to `write_file`.
+                    if let Ok(data) = tokio::fs::read(segment_cache_path(cache_dir, index)).await {
+                        return Ok::<(usize, Vec<u8>), Box<dyn std::error::Error>>((index, data));
+                    }
                 }
-            } else {
-                let key_resp = reqwest::get(format!(
-                    "{}{}",
-                    &m3u8.domain_name,
-                    key_value.get(URL).unwrap()
-                ))
+
+                let buf = with_retry(&segment.uri, max_retries, || {
+                    request_resource(domain_name, &segment.uri, segment.byte_range)
+                })
                 .await?;
-                let key = key_resp.text().await?;
-                for uri in uri_list.iter() {
-                    let buf = request_resource(&m3u8.domain_name, uri).await?;
-                    let mut result = decrypt(key.as_bytes(), &IV, &buf).await?;
-                    let _ = write_file.write_all(&mut result).unwrap();
-                    println!("{}/{}", count, uri_list.len());
-                    count += 1;
+
+                let data =
+                    match resolve_key(&key_cache, domain_name, segment.key.as_ref(), max_retries)
+                        .await?
+                    {
+                        Some(key_text) => {
+                            let iv = resolve_iv(media_sequence, index, segment.key.as_ref());
+                            decrypt(key_text.as_bytes(), &iv, &buf).await?
+                        }
+                        None => buf,
+                    };
+
+                if let Some(cache_dir) = &cache_dir {
+                    // Write to a temporary name and rename, so a part-file only
+                    // ever exists once its contents are complete.
+                    let part_path = segment_cache_path(cache_dir, index);
+                    let tmp_path = part_path.with_extension("part.tmp");
+                    tokio::fs::write(&tmp_path, &data).await?;
+                    tokio::fs::rename(&tmp_path, &part_path).await?;
                 }
+
+                Ok((index, data))
+            }
+        })
+        .buffer_unordered(m3u8.concurrency.max(1));
+
+    // Segments complete out of order under concurrency, so buffer them by
+    // index and flush only the contiguous prefix that is ready.
+    let mut pending: BTreeMap<usize, Vec<u8>> = BTreeMap::new();
+    let mut next_index = 0;
+    let mut bytes_downloaded = 0u64;
+    let mut seconds_downloaded = 0.0_f64;
+    while let Some(result) = fetches.next().await {
+        let (index, data) = result?;
+        bytes_downloaded += data.len() as u64;
+        pending.insert(index, data);
+        while let Some(data) = pending.remove(&next_index) {
+            let segment = &media.segments[next_index];
+            if segment.discontinuity {
+                progress.println(format!("discontinuity before segment {next_index}"));
             }
+            seconds_downloaded += segment.duration.unwrap_or(0.0);
+            write_file.write_all(&data)?;
+            next_index += 1;
+            progress.inc(1);
         }
+        progress.set_message(format!(
+            "{bytes_downloaded} bytes, {seconds_downloaded:.1}s"
+        ));
     }
+    progress.finish_with_message(format!(
+        "{bytes_downloaded} bytes, {seconds_downloaded:.1}s, done"
+    ));
 
     Ok(())
 }
 
+/// Resolves the decryption key text for one segment, fetching it over HTTP
+/// on first use and reusing the cache afterward since an `#EXT-X-KEY` tag
+/// typically governs a run of segments. Returns `None` when the segment is
+/// unencrypted (no key, or `METHOD=NONE`).
+async fn resolve_key(
+    cache: &Mutex<HashMap<String, String>>,
+    domain_name: &str,
+    key: Option<&Key>,
+    max_retries: u32,
+) -> Result<Option<String>, Box<dyn std::error::Error>> {
+    let Some(key) = key else {
+        return Ok(None);
+    };
+    if key.method.eq_ignore_ascii_case("NONE") {
+        return Ok(None);
+    }
+    let Some(uri) = &key.uri else {
+        return Ok(None);
+    };
+
+    if let Some(text) = cache.lock().await.get(uri) {
+        return Ok(Some(text.clone()));
+    }
+
+    let key_url = join_url(domain_name, uri);
+    let key_text = with_retry(&key_url, max_retries, || {
+        let key_url = key_url.clone();
+        async move {
+            let resp = reqwest::get(&key_url).await?.error_for_status()?;
+            Ok(resp.text().await?)
+        }
+    })
+    .await?;
+
+    cache.lock().await.insert(uri.clone(), key_text.clone());
+    Ok(Some(key_text))
+}
+
+fn segment_cache_path(cache_dir: &str, index: usize) -> std::path::PathBuf {
+    std::path::Path::new(cache_dir).join(format!("{index:08}.part"))
+}
+
 async fn request_resource(
-    domain_name: &String,
-    uri: &String,
+    domain_name: &str,
+    uri: &str,
+    byte_range: Option<playlist::ByteRange>,
 ) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
-    let value = reqwest::get(format!("{}/{}", domain_name, uri))
-        .await
-        .unwrap()
+    let request = reqwest::Client::new().get(join_url(domain_name, uri));
+    let request = match byte_range {
+        Some(range) => {
+            let start = range.offset.unwrap_or(0);
+            let end = start + range.length.saturating_sub(1);
+            request.header(reqwest::header::RANGE, format!("bytes={start}-{end}"))
+        }
+        None => request,
+    };
+    let value = request
+        .send()
+        .await?
+        .error_for_status()?
         .bytes()
-        .await
-        .unwrap()
+        .await?
         .to_vec();
     Ok(value)
 }
 
-fn acquire_u32(context: &str, keyword: &str) -> u32 {
-    let data = context.split(keyword);
-    let value = data.last().unwrap().to_string().parse::<u32>().unwrap();
-    value
+/// An HTTP fetch (segment, key, or playlist) that failed after exhausting
+/// all retry attempts. Keeps the URI so the caller can tell which request
+/// gave up.
+#[derive(Debug)]
+struct FetchError {
+    uri: String,
+    attempts: u32,
+    source: Box<dyn std::error::Error>,
 }
 
-fn acquire_string(context: &str, keyword: &str) -> String {
-    let data = context.split(keyword);
-    let value = data.last().unwrap().to_string();
-    value
+impl std::fmt::Display for FetchError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "giving up on {} after {} attempts: {}",
+            self.uri, self.attempts, self.source
+        )
+    }
+}
+
+impl std::error::Error for FetchError {}
+
+/// Runs `attempt` up to `max_retries` times in total (at least once, the
+/// first try included) with exponential backoff between tries
+/// (200ms, 400ms, 800ms, ...), wrapping the last error as a [`FetchError`]
+/// labelled with `uri` once attempts are exhausted.
+async fn with_retry<T, F, Fut>(
+    uri: &str,
+    max_retries: u32,
+    mut attempt: F,
+) -> Result<T, Box<dyn std::error::Error>>
+where
+    F: FnMut() -> Fut,
+    Fut: std::future::Future<Output = Result<T, Box<dyn std::error::Error>>>,
+{
+    let max_retries = max_retries.max(1);
+    let mut last_err = None;
+    for try_number in 0..max_retries {
+        match attempt().await {
+            Ok(value) => return Ok(value),
+            Err(err) => {
+                last_err = Some(err);
+                if try_number + 1 < max_retries {
+                    tokio::time::sleep(backoff_for(try_number)).await;
+                }
+            }
+        }
+    }
+    Err(Box::new(FetchError {
+        uri: uri.to_string(),
+        attempts: max_retries,
+        source: last_err.unwrap(),
+    }))
+}
+
+/// Backoff delay for the given (zero-based) retry attempt: 200ms, 400ms,
+/// 800ms, ..., capped at attempt 10 (~205s) so an oversized `--max-retries`
+/// degrades to a capped delay instead of overflowing `2u64.pow`.
+fn backoff_for(try_number: u32) -> Duration {
+    Duration::from_millis(200 * 2u64.pow(try_number.min(10)))
+}
+
+/// Resolves the AES-128 IV for one segment. Per the HLS spec, if the
+/// `#EXT-X-KEY` tag carries an explicit `IV=0x...` attribute that value is
+/// used for every segment under that key; otherwise the IV is the segment's
+/// media-sequence number, zero-padded into a 128-bit big-endian integer.
+fn resolve_iv(media_sequence: u32, index: usize, key: Option<&Key>) -> [u8; 16] {
+    match key.and_then(|key| key.iv.as_deref()) {
+        Some(hex) => parse_iv_hex(hex),
+        None => (media_sequence as u128 + index as u128).to_be_bytes(),
+    }
+}
+
+fn parse_iv_hex(value: &str) -> [u8; 16] {
+    let hex = value.trim_start_matches("0x").trim_start_matches("0X");
+    // Parse as a big-endian integer rather than copying hex digit pairs into
+    // byte slots left-to-right: a value shorter than 32 digits (legal per
+    // spec when leading zeros are omitted) must be zero-extended on the
+    // high (left) side, not padded with zeros on the low (right) side.
+    let number = u128::from_str_radix(hex, 16).unwrap_or(0);
+    number.to_be_bytes()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn variant_base_resolves_against_its_own_subdirectory() {
+        // Real-world master playlists commonly place each rendition in its
+        // own subdirectory, e.g. `variant_hi/index.m3u8` referencing sibling
+        // `seg0.ts`; segments must resolve against that subdirectory, not
+        // the master playlist's domain.
+        assert_eq!(
+            resolve_variant_base("https://host", "variant_hi/index.m3u8"),
+            "https://host/variant_hi"
+        );
+    }
+
+    #[test]
+    fn variant_base_falls_back_to_domain_when_variant_has_no_directory() {
+        assert_eq!(
+            resolve_variant_base("https://host", "index.m3u8"),
+            "https://host"
+        );
+    }
+
+    #[test]
+    fn join_url_inserts_the_missing_separator() {
+        // A key URI that is a sibling filename (the common case, e.g.
+        // `URI="key.bin"` next to `seg0.ts`) must join with `/`, not get
+        // concatenated straight onto the base.
+        assert_eq!(
+            join_url("https://host/variant_hi", "key.bin"),
+            "https://host/variant_hi/key.bin"
+        );
+    }
+
+    #[test]
+    fn backoff_does_not_overflow_for_large_retry_counts() {
+        // A large --max-retries used to panic ("attempt to multiply with
+        // overflow") once try_number reached 57; it must now cap instead.
+        assert_eq!(backoff_for(10), backoff_for(1000));
+    }
+
+    #[test]
+    fn iv_hex_is_zero_extended_on_the_high_side() {
+        // A short IV (leading zeros omitted) must land in the low-order
+        // bytes, not the high-order ones.
+        assert_eq!(
+            parse_iv_hex("0x1"),
+            [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1]
+        );
+    }
+
+    #[test]
+    fn iv_hex_full_width_round_trips() {
+        assert_eq!(
+            parse_iv_hex("0x000102030405060708090A0B0C0D0E0F"),
+            [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15]
+        );
+    }
 }
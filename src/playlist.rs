@@ -0,0 +1,325 @@
+//! Typed HLS playlist model and parser.
+//!
+//! Replaces the old ad-hoc `starts_with`/`split` line scanning with a proper
+//! tokenizer: unknown tags are skipped instead of silently corrupting state,
+//! and malformed numeric tags are dropped instead of panicking via
+//! `.unwrap()`.
+
+const EXT_X_VERSION: &str = "#EXT-X-VERSION:";
+const EXT_X_TARGETDURATION: &str = "#EXT-X-TARGETDURATION:";
+const EXT_X_PLAYLIST_TYPE: &str = "#EXT-X-PLAYLIST-TYPE:";
+const EXT_X_MEDIA_SEQUENCE: &str = "#EXT-X-MEDIA-SEQUENCE:";
+const EXT_X_ENDLIST: &str = "#EXT-X-ENDLIST";
+const EXT_X_KEY: &str = "#EXT-X-KEY:";
+const EXT_X_STREAM_INF: &str = "#EXT-X-STREAM-INF:";
+const EXT_X_BYTERANGE: &str = "#EXT-X-BYTERANGE:";
+const EXT_X_DISCONTINUITY: &str = "#EXT-X-DISCONTINUITY";
+const EXTINF: &str = "#EXTINF:";
+
+/// A decryption key in effect for one or more subsequent segments, taken
+/// from an `#EXT-X-KEY` tag. `method` is `"NONE"` when encryption is turned
+/// off mid-playlist.
+#[derive(Debug, Clone, Default)]
+pub struct Key {
+    pub method: String,
+    pub uri: Option<String>,
+    pub iv: Option<String>,
+}
+
+/// A byte range requested via `#EXT-X-BYTERANGE:<length>[@<offset>]`. When
+/// `@<offset>` is omitted the range starts right after the previous
+/// segment's range, which the parser resolves while scanning.
+#[derive(Debug, Clone, Copy)]
+pub struct ByteRange {
+    pub length: u64,
+    pub offset: Option<u64>,
+}
+
+/// One media segment, carrying everything needed to fetch, decrypt, and
+/// place it correctly in the final output.
+#[derive(Debug, Clone)]
+pub struct Segment {
+    pub uri: String,
+    pub duration: Option<f64>,
+    pub byte_range: Option<ByteRange>,
+    pub discontinuity: bool,
+    pub key: Option<Key>,
+}
+
+/// A single rendition advertised by a master playlist's `#EXT-X-STREAM-INF` tag.
+#[derive(Debug, Clone)]
+pub struct StreamInf {
+    pub bandwidth: Option<u64>,
+    pub average_bandwidth: Option<u64>,
+    pub resolution: Option<String>,
+    pub codecs: Option<String>,
+    pub uri: String,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct MediaPlaylist {
+    pub version: Option<u32>,
+    pub target_duration: Option<u32>,
+    pub playlist_type: Option<String>,
+    pub media_sequence: Option<u32>,
+    pub end_list: bool,
+    pub segments: Vec<Segment>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct MasterPlaylist {
+    pub variants: Vec<StreamInf>,
+}
+
+#[derive(Debug, Clone)]
+pub enum Playlist {
+    Master(MasterPlaylist),
+    Media(MediaPlaylist),
+}
+
+/// Parses an m3u8 document into either a [`MasterPlaylist`] (variant
+/// renditions) or a [`MediaPlaylist`] (actual segments), depending on which
+/// kind of tags it contains.
+pub fn parse(text: &str) -> Playlist {
+    let lines: Vec<&str> = text.split('\n').collect();
+
+    let mut master = MasterPlaylist::default();
+    let mut media = MediaPlaylist::default();
+
+    let mut pending_key: Option<Key> = None;
+    let mut pending_duration: Option<f64> = None;
+    let mut pending_byte_range: Option<ByteRange> = None;
+    let mut pending_discontinuity = false;
+    let mut last_byte_range_end: Option<u64> = None;
+
+    let mut index = 0;
+    while index < lines.len() {
+        let line = lines[index].trim();
+
+        if line.starts_with(EXT_X_VERSION) {
+            media.version = acquire_u32(line, EXT_X_VERSION);
+        } else if line.starts_with(EXT_X_TARGETDURATION) {
+            media.target_duration = acquire_u32(line, EXT_X_TARGETDURATION);
+        } else if line.starts_with(EXT_X_PLAYLIST_TYPE) {
+            media.playlist_type = Some(acquire_string(line, EXT_X_PLAYLIST_TYPE));
+        } else if line.starts_with(EXT_X_MEDIA_SEQUENCE) {
+            media.media_sequence = acquire_u32(line, EXT_X_MEDIA_SEQUENCE);
+        } else if line.starts_with(EXT_X_ENDLIST) {
+            media.end_list = true;
+        } else if line.starts_with(EXTINF) {
+            pending_duration = line
+                .trim_start_matches(EXTINF)
+                .split(',')
+                .next()
+                .and_then(|value| value.trim().parse().ok());
+        } else if line.starts_with(EXT_X_BYTERANGE) {
+            pending_byte_range = parse_byte_range(
+                line.trim_start_matches(EXT_X_BYTERANGE),
+                last_byte_range_end,
+            );
+            if let Some(range) = pending_byte_range {
+                last_byte_range_end = Some(range.offset.unwrap_or(0) + range.length);
+            }
+        } else if line == EXT_X_DISCONTINUITY {
+            pending_discontinuity = true;
+        } else if line.starts_with(EXT_X_KEY) {
+            pending_key = Some(parse_key(line.trim_start_matches(EXT_X_KEY)));
+        } else if line.starts_with(EXT_X_STREAM_INF) {
+            let attrs = line.trim_start_matches(EXT_X_STREAM_INF);
+            if let Some(uri_line) = lines.get(index + 1) {
+                let uri = uri_line.trim();
+                if !uri.is_empty() {
+                    master.variants.push(parse_stream_inf(attrs, uri));
+                    index += 1;
+                }
+            }
+        } else if !line.is_empty() && !line.starts_with('#') {
+            media.segments.push(Segment {
+                uri: line.to_string(),
+                duration: pending_duration.take(),
+                byte_range: pending_byte_range.take(),
+                discontinuity: std::mem::take(&mut pending_discontinuity),
+                key: pending_key.clone(),
+            });
+        }
+
+        index += 1;
+    }
+
+    if !master.variants.is_empty() {
+        Playlist::Master(master)
+    } else {
+        Playlist::Media(media)
+    }
+}
+
+/// Picks the rendition to download out of a master playlist's variants:
+/// `highest`/`lowest` bandwidth, an explicit resolution (e.g. `1920x1080`),
+/// or an explicit bandwidth number.
+pub fn select_variant<'a>(variants: &'a [StreamInf], variant: &str) -> &'a StreamInf {
+    match variant {
+        "highest" => variants
+            .iter()
+            .max_by_key(|v| v.bandwidth.unwrap_or(0))
+            .unwrap(),
+        "lowest" => variants
+            .iter()
+            .min_by_key(|v| v.bandwidth.unwrap_or(0))
+            .unwrap(),
+        explicit => variants
+            .iter()
+            .find(|v| v.resolution.as_deref() == Some(explicit))
+            .or_else(|| {
+                let bandwidth: u64 = explicit.parse().ok()?;
+                variants.iter().find(|v| v.bandwidth == Some(bandwidth))
+            })
+            .unwrap_or(&variants[0]),
+    }
+}
+
+fn parse_key(attrs: &str) -> Key {
+    let mut key = Key::default();
+    for (name, value) in parse_attribute_list(attrs) {
+        match name.as_str() {
+            "METHOD" => key.method = value,
+            "URI" => key.uri = Some(value),
+            "IV" => key.iv = Some(value),
+            _ => {}
+        }
+    }
+    key
+}
+
+fn parse_stream_inf(attrs: &str, uri: &str) -> StreamInf {
+    let mut bandwidth = None;
+    let mut average_bandwidth = None;
+    let mut resolution = None;
+    let mut codecs = None;
+
+    for (name, value) in parse_attribute_list(attrs) {
+        match name.as_str() {
+            "AVERAGE-BANDWIDTH" => average_bandwidth = value.parse().ok(),
+            "BANDWIDTH" => bandwidth = value.parse().ok(),
+            "RESOLUTION" => resolution = Some(value),
+            "CODECS" => codecs = Some(value),
+            _ => {}
+        }
+    }
+
+    StreamInf {
+        bandwidth,
+        average_bandwidth,
+        resolution,
+        codecs,
+        uri: uri.to_string(),
+    }
+}
+
+fn parse_byte_range(value: &str, previous_end: Option<u64>) -> Option<ByteRange> {
+    let mut parts = value.splitn(2, '@');
+    let length = parts.next()?.trim().parse().ok()?;
+    let offset = match parts.next() {
+        Some(offset) => offset.trim().parse().ok(),
+        None => previous_end,
+    };
+    Some(ByteRange { length, offset })
+}
+
+/// Splits an HLS attribute list (the part of a tag after the `:`) into
+/// `(NAME, value)` pairs. Commas are only treated as separators outside of
+/// double quotes, so values like `URI="https://host/key?a,b"` or
+/// `CODECS="avc1.4d401f,mp4a.40.2"` survive intact. Surrounding quotes on the
+/// value are stripped.
+fn parse_attribute_list(input: &str) -> Vec<(String, String)> {
+    let mut tokens = Vec::new();
+    let mut current = String::new();
+    let mut in_quotes = false;
+
+    for ch in input.chars() {
+        match ch {
+            '"' => {
+                in_quotes = !in_quotes;
+                current.push(ch);
+            }
+            ',' if !in_quotes => {
+                tokens.push(std::mem::take(&mut current));
+            }
+            _ => current.push(ch),
+        }
+    }
+    if !current.is_empty() {
+        tokens.push(current);
+    }
+
+    tokens
+        .into_iter()
+        .filter_map(|token| {
+            let (name, value) = token.trim().split_once('=')?;
+            Some((
+                name.trim().to_string(),
+                value.trim().trim_matches('"').to_string(),
+            ))
+        })
+        .collect()
+}
+
+fn acquire_u32(context: &str, keyword: &str) -> Option<u32> {
+    context.strip_prefix(keyword)?.trim().parse().ok()
+}
+
+fn acquire_string(context: &str, keyword: &str) -> String {
+    context
+        .strip_prefix(keyword)
+        .unwrap_or_default()
+        .trim()
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn attribute_list_survives_quoted_commas() {
+        let attrs = parse_attribute_list(
+            r#"METHOD=AES-128,URI="https://host/key?a,b",CODECS="avc1.4d401f,mp4a.40.2""#,
+        );
+        assert_eq!(
+            attrs,
+            vec![
+                ("METHOD".to_string(), "AES-128".to_string()),
+                ("URI".to_string(), "https://host/key?a,b".to_string()),
+                ("CODECS".to_string(), "avc1.4d401f,mp4a.40.2".to_string()),
+            ]
+        );
+    }
+
+    fn variant(bandwidth: u64, resolution: &str, uri: &str) -> StreamInf {
+        StreamInf {
+            bandwidth: Some(bandwidth),
+            average_bandwidth: None,
+            resolution: Some(resolution.to_string()),
+            codecs: None,
+            uri: uri.to_string(),
+        }
+    }
+
+    #[test]
+    fn select_variant_picks_highest_and_lowest_bandwidth() {
+        let variants = vec![
+            variant(800_000, "640x360", "low.m3u8"),
+            variant(2_800_000, "1920x1080", "high.m3u8"),
+        ];
+        assert_eq!(select_variant(&variants, "highest").uri, "high.m3u8");
+        assert_eq!(select_variant(&variants, "lowest").uri, "low.m3u8");
+    }
+
+    #[test]
+    fn select_variant_matches_explicit_resolution() {
+        let variants = vec![
+            variant(800_000, "640x360", "low.m3u8"),
+            variant(2_800_000, "1920x1080", "high.m3u8"),
+        ];
+        assert_eq!(select_variant(&variants, "640x360").uri, "low.m3u8");
+    }
+}